@@ -11,7 +11,256 @@ use std::collections::HashMap;
 use rayon::prelude::*;
 use std::sync::{Arc, RwLock};
 use std::env;
+use serde::{Deserialize, Serialize};
 
+/// One layer's palette and output filename, as loaded from a palette config file.
+#[derive(Debug, Deserialize)]
+struct LayerConfig {
+    /// Colors indexed by class value, i.e. `colors[class]` is the RGB for that class.
+    colors: Vec<[u8; 3]>,
+    /// Filename the rendered layer is saved as, relative to the output folder.
+    output: String,
+}
+
+/// Top-level palette config: which layers to render and how to color them.
+///
+/// Keys select among the layers the pipeline already computes ("terrain",
+/// "vegetation", "temperature", "moisture", "biome") — the config can pick a
+/// subset and rename its output file, but it can't introduce a layer derived
+/// from a new DBF field on its own; that still requires adding the field to
+/// `PixelMapping`/`World`, not just editing this config. An unrecognized key
+/// is reported as a warning and skipped, rather than silently ignored.
+///
+/// Concretely: retargeting this tool to a DBF schema with an extra field
+/// (drainage, salinity, volcanism, ...) is *not* purely a config change —
+/// it needs a source change to carry that field through `PixelMapping` and
+/// `World` before a config key for it means anything.
+///
+/// `colors` only recolors the four class-indexed layers (`terrain`,
+/// `vegetation`, `temperature`, `moisture`). `biome` isn't a class palette —
+/// it's a blend of the temperature/moisture corner colors in `BIOME_COLORS`
+/// — so a non-empty `colors` list under `biome` is ignored with a warning
+/// rather than silently; only its `output` is honored.
+#[derive(Debug, Deserialize)]
+struct PaletteConfig {
+    layers: HashMap<String, LayerConfig>,
+}
+
+/// A layer's palette as a class-index -> color lookup, plus where to save it.
+struct Palette {
+    colors: Vec<Rgb<u8>>,
+    output: String,
+}
+
+/// Loads a palette config from a TOML or JSON file (by extension), falling back to
+/// `None` when `path` is `None` so callers can use the built-in palette instead.
+///
+/// A missing or malformed config is reported as a `ConvertError::Config` rather
+/// than a panic, so a typo in one run doesn't take down a long batch job.
+fn load_palette_config(path: Option<&str>) -> Result<Option<HashMap<String, Palette>>, ConvertError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| ConvertError::Config(format!("failed to read {}: {}", path, e)))?;
+    let config: PaletteConfig = if path.ends_with(".json") {
+        serde_json::from_str(&contents)
+            .map_err(|e| ConvertError::Config(format!("invalid JSON in {}: {}", path, e)))?
+    } else {
+        toml::from_str(&contents)
+            .map_err(|e| ConvertError::Config(format!("invalid TOML in {}: {}", path, e)))?
+    };
+    Ok(Some(
+        config
+            .layers
+            .into_iter()
+            .map(|(name, layer)| {
+                if name == "biome" && !layer.colors.is_empty() {
+                    eprintln!(
+                        "warning: palette config recolors \"biome\", but biome blends the temperature/moisture \
+                         corner colors rather than using a class palette; its colors are ignored, only output is honored"
+                    );
+                }
+                let colors = layer.colors.into_iter().map(Rgb).collect();
+                (name, Palette { colors, output: layer.output })
+            })
+            .collect(),
+    ))
+}
+
+/// Looks up `class` in `palette`, falling back to `fallback` when the palette is
+/// absent or doesn't cover that class.
+fn palette_color(palette: Option<&Palette>, class: u16, fallback: Rgb<u8>) -> Rgb<u8> {
+    match palette.and_then(|p| p.colors.get(class as usize)) {
+        Some(color) => *color,
+        None => fallback,
+    }
+}
+
+/// The built-in color for `class` on `layer`, used when no config palette
+/// covers it.
+fn default_color(layer: &str, class: u16) -> Rgb<u8> {
+    match layer {
+        "terrain" => match class {
+            1 => Rgb([128, 128, 128]), // Mountains (gray)
+            2 => Rgb([139, 69, 19]),   // Hills (brown)
+            3 => Rgb([232, 193, 148]), // Tablelands (light brown)
+            4 => Rgb([98, 188, 47]),   // Plains (green)
+            _ => Rgb([35, 137, 218]),   // Water (blue)
+        },
+        "vegetation" => match class {
+            1 => Rgb([0, 128, 0]),     // Cropland (green)
+            2 => Rgb([139, 69, 19]),   // Shrubland (brown)
+            3 => Rgb([0, 128, 0]),     // Forest (green)
+            4 => Rgb([0, 255, 0]),     // Grassland (bright green)
+            5 => Rgb([255, 0, 0]),     // Settlement (red)
+            6 => Rgb([128, 128, 128]), // Sparsely or Non-vegetated (gray)
+            8 => Rgb([255, 255, 255]), // Snow and Ice (white)
+            _ => Rgb([35, 137, 218]),   // Not Land (blue)
+        },
+        "temperature" => match class {
+            1 => Rgb([0, 0, 255]),     // Boreal (blue)
+            2 => Rgb([0, 128, 255]),   // Cool Temperate (light blue)
+            3 => Rgb([0, 255, 255]),   // Warm Temperate (cyan)
+            4 => Rgb([255, 255, 0]),   // Sub Tropical (yellow)
+            5 => Rgb([255, 0, 0]),     // Tropical (red)
+            6 => Rgb([255, 255, 255]), // Polar (white)
+            _ => Rgb([35, 137, 218]),   // Not Land (blue)
+        },
+        "moisture" => match class {
+            1 => Rgb([255, 255, 0]), // Desert (yellow)
+            2 => Rgb([255, 128, 0]), // Dry (orange)
+            3 => Rgb([0, 255, 0]),   // Moist (green)
+            _ => Rgb([35, 137, 218]), // Not Land (blue)
+        },
+        _ => Rgb([35, 137, 218]),
+    }
+}
+
+/// The color to render `class` on `layer`, preferring the config palette
+/// (if any covers `layer`) over the built-in one.
+fn layer_color(layer: &str, class: u16, palettes: Option<&HashMap<String, Palette>>) -> Rgb<u8> {
+    let fallback = default_color(layer, class);
+    match palettes.and_then(|p| p.get(layer)) {
+        Some(palette) => palette_color(Some(palette), class, fallback),
+        None => fallback,
+    }
+}
+
+/// Number of temperature classes a `PixelMapping` can carry (1..=6).
+const TEMP_CLASSES: usize = 6;
+/// Number of moisture classes a `PixelMapping` can carry (1..=3).
+const MOIST_CLASSES: usize = 3;
+
+/// Base biome color for each (temperature class, moisture class) pair,
+/// indexed `[temperature][moisture]`. Index 0 on either axis is "no
+/// data" (out of bounds / unmapped) and uses the same blue as the other
+/// layers' fallback color.
+const BIOME_COLORS: [[Rgb<u8>; MOIST_CLASSES + 1]; TEMP_CLASSES + 1] = [
+    [Rgb([35, 137, 218]), Rgb([35, 137, 218]), Rgb([35, 137, 218]), Rgb([35, 137, 218])], // 0: no data
+    [Rgb([35, 137, 218]), Rgb([176, 186, 186]), Rgb([94, 140, 107]), Rgb([27, 92, 59])],   // 1: Boreal
+    [Rgb([35, 137, 218]), Rgb([209, 202, 137]), Rgb([141, 182, 101]), Rgb([45, 120, 63])],  // 2: Cool Temperate
+    [Rgb([35, 137, 218]), Rgb([222, 190, 99]),  Rgb([175, 148, 64]), Rgb([46, 139, 60])],   // 3: Warm Temperate
+    [Rgb([35, 137, 218]), Rgb([224, 160, 74]),  Rgb([189, 176, 56]), Rgb([35, 120, 45])],   // 4: Sub Tropical
+    [Rgb([35, 137, 218]), Rgb([214, 120, 60]),  Rgb([196, 178, 48]), Rgb([20, 100, 40])],   // 5: Tropical
+    [Rgb([35, 137, 218]), Rgb([245, 245, 245]), Rgb([235, 240, 245]), Rgb([225, 235, 245])], // 6: Polar
+];
+
+/// Fraction of `classes` falling into each class value `0..num_classes`.
+/// A class outside that range is bucketed into index 0 ("no data"), the
+/// same way `default_color`'s `_ =>` arm falls back for every other layer.
+fn class_fractions(classes: &[u16], num_classes: usize) -> Vec<f32> {
+    let mut counts = vec![0u32; num_classes];
+    for &class in classes {
+        let idx = class as usize;
+        counts[if idx < num_classes { idx } else { 0 }] += 1;
+    }
+    let total = classes.len().max(1) as f32;
+    counts.into_iter().map(|count| count as f32 / total).collect()
+}
+
+/// Blends `BIOME_COLORS` corners by the fractional temperature/moisture
+/// class composition of a cell's source-pixel block, instead of snapping
+/// to one `most_common` class, so neighboring biomes fade into each other.
+fn biome_color(temperature: &[u16], moisture: &[u16]) -> Rgb<u8> {
+    let temp_fractions = class_fractions(temperature, TEMP_CLASSES + 1);
+    let moist_fractions = class_fractions(moisture, MOIST_CLASSES + 1);
+
+    let mut blended = [0f32; 3];
+    for (t, &temp_fraction) in temp_fractions.iter().enumerate() {
+        if temp_fraction == 0.0 {
+            continue;
+        }
+        for (m, &moist_fraction) in moist_fractions.iter().enumerate() {
+            let weight = temp_fraction * moist_fraction;
+            if weight == 0.0 {
+                continue;
+            }
+            let Rgb(corner) = BIOME_COLORS[t][m];
+            for channel in 0..3 {
+                blended[channel] += weight * corner[channel] as f32;
+            }
+        }
+    }
+    Rgb([
+        blended[0].round() as u8,
+        blended[1].round() as u8,
+        blended[2].round() as u8,
+    ])
+}
+
+/// Everything that can go wrong converting a GeoTIFF, short of a single
+/// unmapped pixel (which is recovered from rather than treated as fatal;
+/// see `World::missing_values`).
+#[derive(Debug)]
+enum ConvertError {
+    /// No DBF record maps this source pixel value.
+    MissingMapping(u16),
+    /// The TIFF decoded to something other than 16-bit samples.
+    UnsupportedFormat,
+    DbfRead(dbase::Error),
+    Tiff(tiff::TiffError),
+    Io(std::io::Error),
+    /// A palette config file was missing, unreadable, or failed to parse.
+    Config(String),
+    /// A CLI argument was malformed, or the tile server failed to start.
+    Cli(String),
+}
+
+impl std::fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvertError::MissingMapping(value) => write!(f, "no DBF mapping for pixel value {}", value),
+            ConvertError::UnsupportedFormat => write!(f, "unsupported GeoTIFF sample format (expected 16-bit)"),
+            ConvertError::DbfRead(e) => write!(f, "failed to read DBF: {}", e),
+            ConvertError::Tiff(e) => write!(f, "failed to read GeoTIFF: {}", e),
+            ConvertError::Io(e) => write!(f, "I/O error: {}", e),
+            ConvertError::Config(msg) => write!(f, "palette config error: {}", msg),
+            ConvertError::Cli(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<dbase::Error> for ConvertError {
+    fn from(e: dbase::Error) -> Self {
+        ConvertError::DbfRead(e)
+    }
+}
+
+impl From<tiff::TiffError> for ConvertError {
+    fn from(e: tiff::TiffError) -> Self {
+        ConvertError::Tiff(e)
+    }
+}
+
+impl From<std::io::Error> for ConvertError {
+    fn from(e: std::io::Error) -> Self {
+        ConvertError::Io(e)
+    }
+}
 
 struct PixelMapping {
     value: u16,
@@ -21,9 +270,9 @@ struct PixelMapping {
     moisture: u16,
 }
 
-fn read_database_mappings(dbf_path: &str) -> Vec<PixelMapping> {
+fn read_database_mappings(dbf_path: &str) -> Result<Vec<PixelMapping>, ConvertError> {
     let mut mappings = Vec::new();
-    let records = dbase::read(dbf_path).unwrap();
+    let records = dbase::read(dbf_path)?;
     for record in records {
         let mut pixel = PixelMapping { value: 0, terrain: 0, vegetation: 0, temperature: 0, moisture: 0 };
         for (name, value) in record {
@@ -43,42 +292,271 @@ fn read_database_mappings(dbf_path: &str) -> Vec<PixelMapping> {
         }
         mappings.push(pixel);
     }
-    return mappings;
+    Ok(mappings)
 }
 
 
 
-fn decode_image(file_path: &str, database_path: &str, scale: u32) -> Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> {
-    println!("Loading image...");
-    let file = File::open(file_path).unwrap();
-    let mut decoder = Decoder::new(BufReader::new(file)).expect("decoder failed").with_limits(Limits::unlimited());
+/// The fully mapped class grid for a world, at source-pixel resolution.
+///
+/// Building this is the expensive part of a conversion (TIFF decode + DBF
+/// mapping), so it's what gets cached to disk by [`save_world_cache`].
+#[derive(Serialize, Deserialize)]
+struct World {
+    width: u32,
+    height: u32,
+    terrain: Vec<u16>,
+    vegetation: Vec<u16>,
+    temperature: Vec<u16>,
+    moisture: Vec<u16>,
+    /// Source pixel values that had no DBF mapping and were left as 0
+    /// ("unknown"), sorted and deduplicated.
+    missing_values: Vec<u16>,
+}
+
+/// Direct-indexed `pixel value -> mapping` lookup, replacing a linear scan
+/// over the DBF records with a constant-time array index.
+struct MappingTable {
+    entries: Vec<Option<PixelMapping>>,
+    /// The largest `Value` the DBF itself declares; `declared_gaps` only
+    /// looks at indices up to here so it doesn't re-report pixel values
+    /// that are simply outside the DBF's range (those are reported as
+    /// `World::missing_values` instead, when a pixel actually hits them).
+    max_declared_value: usize,
+}
 
-    let src_pixels = match decoder.read_image().unwrap() {
-        tiff::decoder::DecodingResult::U16(src_pixels) => src_pixels,
-        _ => panic!("Unsupported image format")
-    };
-    let (width, height) = decoder.dimensions().unwrap();
+impl MappingTable {
+    /// Builds a table covering every value in `0..len`, where `len` is the
+    /// largest of the DBF's own `Value` range and `min_len` (the range of
+    /// pixel values actually present in the source image), so indexing
+    /// never goes out of bounds for either source.
+    fn build(mappings: Vec<PixelMapping>, min_len: usize) -> MappingTable {
+        let max_declared_value = mappings.iter().map(|m| m.value).max().unwrap_or(0) as usize;
+        let len = (max_declared_value + 1).max(min_len);
+        let mut entries = (0..len).map(|_| None).collect::<Vec<_>>();
+        for mapping in mappings {
+            let value = mapping.value as usize;
+            entries[value] = Some(mapping);
+        }
+        MappingTable { entries, max_declared_value }
+    }
+
+    fn get(&self, pixel_value: u16) -> Result<&PixelMapping, ConvertError> {
+        self.entries
+            .get(pixel_value as usize)
+            .and_then(|entry| entry.as_ref())
+            .ok_or(ConvertError::MissingMapping(pixel_value))
+    }
+
+    /// Values within the DBF's own declared range (`0..=max_declared_value`)
+    /// that have no mapping, i.e. actual gaps in the DBF's coverage rather
+    /// than pixel values that simply fall outside its range. Used to
+    /// validate coverage up front.
+    fn declared_gaps(&self) -> Vec<u16> {
+        self.entries[..=self.max_declared_value]
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_none())
+            .map(|(value, _)| value as u16)
+            .collect()
+    }
+}
+
+/// Maps every source pixel to its terrain/vegetation/temperature/moisture
+/// class, once, so downstream rendering never has to touch the DBF again.
+/// Pixels with no DBF mapping are left at class 0 ("unknown") rather than
+/// aborting the whole conversion; their source values are collected into
+/// `World::missing_values` so the caller can report them once at the end.
+fn build_world(src_pixels: &[u16], database_path: &str, width: u32, height: u32) -> Result<World, ConvertError> {
+    let max_pixel_value = *src_pixels.iter().max().unwrap_or(&0);
+    let mappings = read_database_mappings(database_path)?;
+    let table = MappingTable::build(mappings, max_pixel_value as usize + 1);
+
+    let gaps = table.declared_gaps();
+    if !gaps.is_empty() {
+        eprintln!("warning: DBF has no mapping for {} value(s) in its own range: {:?}", gaps.len(), gaps);
+    }
+
+    let mut terrain = vec![0u16; src_pixels.len()];
+    let mut vegetation = vec![0u16; src_pixels.len()];
+    let mut temperature = vec![0u16; src_pixels.len()];
+    let mut moisture = vec![0u16; src_pixels.len()];
+    let missing_values = std::sync::Mutex::new(std::collections::BTreeSet::new());
+
+    terrain
+        .par_iter_mut()
+        .zip(vegetation.par_iter_mut())
+        .zip(temperature.par_iter_mut())
+        .zip(moisture.par_iter_mut())
+        .zip(src_pixels.par_iter())
+        .for_each(|((((t, v), te), m), &pixel_value)| {
+            match table.get(pixel_value) {
+                Ok(mapping) => {
+                    *t = mapping.terrain;
+                    *v = mapping.vegetation;
+                    *te = mapping.temperature;
+                    *m = mapping.moisture;
+                }
+                Err(ConvertError::MissingMapping(value)) => {
+                    missing_values.lock().unwrap().insert(value);
+                }
+                Err(_) => {}
+            }
+        });
+
+    let missing_values = missing_values.into_inner().unwrap().into_iter().collect();
+    Ok(World { width, height, terrain, vegetation, temperature, moisture, missing_values })
+}
 
+/// Where the cached `World` for `file_path` is stored.
+fn world_cache_path(file_path: &str) -> String {
+    format!("{}.world.bin", file_path)
+}
 
-    let images = set_pixels(src_pixels, database_path, scale, width, height);
+/// Seconds-since-epoch mtime of `database_path`, used to key the cache.
+fn dbf_modified_secs(database_path: &str) -> u64 {
+    std::fs::metadata(database_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
-    return images;
+/// Loads a cached `World` for `file_path`, if one exists and is still valid
+/// for the current input path and DBF modification time.
+fn load_world_cache(file_path: &str, database_path: &str) -> Option<World> {
+    let bytes = std::fs::read(world_cache_path(file_path)).ok()?;
+    let (cached_path, cached_dbf_modified, world): (String, u64, World) =
+        bincode::deserialize(&bytes).ok()?;
+    if cached_path == file_path && cached_dbf_modified == dbf_modified_secs(database_path) {
+        Some(world)
+    } else {
+        None
+    }
 }
 
-fn set_pixels(src_pixels: Vec<u16>, database_path: &str, scale: u32, width: u32, height: u32) -> Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> {
+/// Saves `world` to disk, keyed on `file_path` and the current DBF mtime.
+fn save_world_cache(file_path: &str, database_path: &str, world: &World) {
+    let path = world_cache_path(file_path);
+    let payload = (file_path, dbf_modified_secs(database_path), world);
+    match bincode::serialize(&payload) {
+        Ok(bytes) => {
+            if let Err(e) = std::fs::write(&path, bytes) {
+                eprintln!("warning: failed to write world cache {}: {}", path, e);
+            }
+        }
+        Err(e) => eprintln!("warning: failed to serialize world cache: {}", e),
+    }
+}
 
-    let mut image_terrain = ImageBuffer::new(width / scale, height / scale);
-    let image_vegetation = Arc::new(RwLock::new(ImageBuffer::new(width / scale, height / scale)));
-    let image_temperature = Arc::new(RwLock::new(ImageBuffer::new(width / scale, height / scale)));
-    let image_moisture = Arc::new(RwLock::new(ImageBuffer::new(width / scale, height / scale)));
+/// Loads the cached `World` for `file_path` if it's still valid, otherwise
+/// decodes the GeoTIFF, maps it against `database_path`, and caches it.
+fn load_or_build_world(file_path: &str, database_path: &str) -> Result<World, ConvertError> {
+    match load_world_cache(file_path, database_path) {
+        Some(world) => {
+            println!("Using cached world grid...");
+            Ok(world)
+        }
+        None => {
+            println!("Loading image...");
+            let file = File::open(file_path)?;
+            let mut decoder = Decoder::new(BufReader::new(file))?.with_limits(Limits::unlimited());
+
+            let src_pixels = match decoder.read_image()? {
+                tiff::decoder::DecodingResult::U16(src_pixels) => src_pixels,
+                _ => return Err(ConvertError::UnsupportedFormat),
+            };
+            let (width, height) = decoder.dimensions()?;
+
+            let world = build_world(&src_pixels, database_path, width, height)?;
+            save_world_cache(file_path, database_path, &world);
+            Ok(world)
+        }
+    }
+}
+
+/// Elevation rank derived from a terrain class, for hillshading:
+/// Mountains > Hills > Tablelands > Plains > Water.
+fn terrain_elevation(terrain_class: u16) -> i16 {
+    match terrain_class {
+        1 => 4, // Mountains
+        2 => 3, // Hills
+        3 => 2, // Tablelands
+        4 => 1, // Plains
+        _ => 0, // Water
+    }
+}
+
+/// Brightens or darkens each pixel of `image` by comparing its elevation
+/// to the cell immediately to the north (one row up): higher than its
+/// northern neighbor gets `+delta`, lower gets `-delta`, clamped to
+/// 0..=255. `elevation` is row-major at the same resolution as `image`.
+fn apply_hillshade(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, elevation: &[i16], delta: i16) {
+    let width = image.width();
+    for y in (1..image.height()).rev() {
+        for x in 0..width {
+            let current = elevation[(y * width + x) as usize];
+            let north = elevation[((y - 1) * width + x) as usize];
+            let adjust = match current.cmp(&north) {
+                std::cmp::Ordering::Greater => delta,
+                std::cmp::Ordering::Less => -delta,
+                std::cmp::Ordering::Equal => continue,
+            };
+            let Rgb(rgb) = *image.get_pixel(x, y);
+            let shaded = rgb.map(|channel| (channel as i32 + adjust as i32).clamp(0, 255) as u8);
+            image.put_pixel(x, y, Rgb(shaded));
+        }
+    }
+}
+
+/// The rendered layer images returned by `decode_image`/`set_pixels`, one per
+/// layer: terrain, vegetation, temperature, moisture, biome.
+type LayerImages = Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>;
+
+fn decode_image(
+    file_path: &str,
+    database_path: &str,
+    scale: u32,
+    palettes: Option<&HashMap<String, Palette>>,
+    hillshade_delta: Option<i16>,
+) -> Result<LayerImages, ConvertError> {
+    let world = load_or_build_world(file_path, database_path)?;
+    if !world.missing_values.is_empty() {
+        eprintln!(
+            "warning: {} unmapped pixel value(s) rendered as unknown: {:?}",
+            world.missing_values.len(),
+            world.missing_values
+        );
+    }
+    set_pixels(&world, scale, palettes, hillshade_delta)
+}
+
+fn set_pixels(
+    world: &World,
+    scale: u32,
+    palettes: Option<&HashMap<String, Palette>>,
+    hillshade_delta: Option<i16>,
+) -> Result<LayerImages, ConvertError> {
+    let width = world.width;
+    let height = world.height;
+    let out_width = width / scale;
+    let out_height = height / scale;
+
+    let mut image_terrain = ImageBuffer::new(out_width, out_height);
+    let image_vegetation = Arc::new(RwLock::new(ImageBuffer::new(out_width, out_height)));
+    let image_temperature = Arc::new(RwLock::new(ImageBuffer::new(out_width, out_height)));
+    let image_moisture = Arc::new(RwLock::new(ImageBuffer::new(out_width, out_height)));
+    let image_biome = Arc::new(RwLock::new(ImageBuffer::new(out_width, out_height)));
+    let elevation = Arc::new(RwLock::new(vec![0i16; (out_width * out_height) as usize]));
 
     // Clone Arc references for each image
     let image_vegetation_clone = Arc::clone(&image_vegetation);
     let image_temperature_clone = Arc::clone(&image_temperature);
     let image_moisture_clone = Arc::clone(&image_moisture);
+    let image_biome_clone = Arc::clone(&image_biome);
+    let elevation_clone = Arc::clone(&elevation);
 
-    let max_db_value = src_pixels.iter().max().unwrap();
-    let mappings = read_database_mappings(database_path);
     tqdm(image_terrain.enumerate_pixels_mut()).par_bridge().for_each(|(x, y, pixel)| {
         let mut terrain = Vec::new();
         let mut vegetation  = Vec::new();
@@ -87,76 +565,180 @@ fn set_pixels(src_pixels: Vec<u16>, database_path: &str, scale: u32, width: u32,
         for i in 0..scale {
             for j in 0..scale {
                 let index = ((y as u64 * scale as u64 + j as u64) * width as u64 + (x as u64 * scale as u64 + i as u64)) as usize;
-                let pixel_value = src_pixels[index];
-                if pixel_value < *max_db_value {
-                    let mapping = map_pixel(pixel_value, &mappings);
-                    terrain.push(mapping.terrain);
-                    vegetation.push(mapping.vegetation);
-                    temperature.push(mapping.temperature);
-                    moisture.push(mapping.moisture);
-                } else {
-                    terrain.push(0);
-                    vegetation.push(0);
-                    temperature.push(0);
-                    moisture.push(0);
-                }
+                terrain.push(world.terrain[index]);
+                vegetation.push(world.vegetation[index]);
+                temperature.push(world.temperature[index]);
+                moisture.push(world.moisture[index]);
             }
         }
-        let color = match most_common(&terrain){
-            1 => Rgb([128, 128, 128]), // Mountains (gray)
-            2 => Rgb([139, 69, 19]),   // Hills (brown)
-            3 => Rgb([232, 193, 148]), // Tablelands (light brown)
-            4 => Rgb([98, 188, 47]),   // Plains (green)
-            _ => Rgb([35,137,218]),     // Water (blue)
-        };
+        let terrain_class = most_common(&terrain);
+        let color = layer_color("terrain", terrain_class, palettes);
         *pixel = color;
+        if hillshade_delta.is_some() {
+            elevation_clone.write().unwrap()[(y * out_width + x) as usize] = terrain_elevation(terrain_class);
+        }
 
-        let color = match most_common(&vegetation) {
-            1 => Rgb([0, 128, 0]),    // Cropland (green)
-            2 => Rgb([139, 69, 19]),  // Shrubland (brown)
-            3 => Rgb([0, 128, 0]),    // Forest (green)
-            4 => Rgb([0, 255, 0]),    // Grassland (bright green)
-            5 => Rgb([255, 0, 0]),    // Settlement (red)
-            6 => Rgb([128, 128, 128]), // Sparsely or Non-vegetated (gray)
-            8 => Rgb([255, 255, 255]), // Snow and Ice (white)
-            _ => Rgb([35,137,218]),      // Not Land (black)
-        };
+        let color = layer_color("vegetation", most_common(&vegetation), palettes);
         image_vegetation_clone.write().unwrap().put_pixel(x, y, color);
 
-        let color = match most_common(&temperature) {
-            1 => Rgb([0, 0, 255]),    // Boreal (blue)
-            2 => Rgb([0, 128, 255]),  // Cool Temperate (light blue)
-            3 => Rgb([0, 255, 255]),  // Warm Temperate (cyan)
-            4 => Rgb([255, 255, 0]),  // Sub Tropical (yellow)
-            5 => Rgb([255, 0, 0]),    // Tropical (red)
-            6 => Rgb([255, 255, 255]), // Polar (white)
-            _ => Rgb([35,137,218]),      // Not Land (black)
-        };
+        let color = layer_color("temperature", most_common(&temperature), palettes);
         image_temperature_clone.write().unwrap().put_pixel(x, y, color);
 
-        let color = match most_common(&moisture) {
-            1 => Rgb([255, 255, 0]), // Desert (yellow)
-            2 => Rgb([255, 128, 0]), // Dry (orange)
-            3 => Rgb([0, 255, 0]),   // Moist (green)
-            _ => Rgb([35,137,218]),     // Not Land (black)
-        };
+        let color = layer_color("moisture", most_common(&moisture), palettes);
         image_moisture_clone.write().unwrap().put_pixel(x, y, color);
+
+        let color = biome_color(&temperature, &moisture);
+        image_biome_clone.write().unwrap().put_pixel(x, y, color);
     });
-    return vec![
+
+    if let Some(delta) = hillshade_delta {
+        apply_hillshade(&mut image_terrain, &elevation.read().unwrap(), delta);
+    }
+
+    let images = vec![
         image_terrain,
         image_vegetation.read().unwrap().clone(),
         image_temperature.read().unwrap().clone(),
         image_moisture.read().unwrap().clone(),
+        image_biome.read().unwrap().clone(),
     ];
+    Ok(images)
+}
+
+/// The `World`'s per-pixel class grid for `layer`, or `None` if `layer`
+/// isn't one of the known layer names.
+fn layer_classes<'a>(world: &'a World, layer: &str) -> Option<&'a [u16]> {
+    match layer {
+        "terrain" => Some(&world.terrain),
+        "vegetation" => Some(&world.vegetation),
+        "temperature" => Some(&world.temperature),
+        "moisture" => Some(&world.moisture),
+        _ => None,
+    }
+}
+
+/// The source-pixel window a slippy-map tile covers, and the tile's own
+/// pixel side length.
+struct TileWindow {
+    origin_x: u32,
+    origin_y: u32,
+    width: u32,
+    height: u32,
+    tile_size: u32,
+}
+
+impl TileWindow {
+    /// The window covered by tile `(z, x, y)` of `world`, rendered at
+    /// `tile_size` pixels per side.
+    fn new(world: &World, z: u32, x: u32, y: u32, tile_size: u32) -> TileWindow {
+        let tiles_per_axis = 1u32 << z;
+        let width = (world.width / tiles_per_axis).max(1);
+        let height = (world.height / tiles_per_axis).max(1);
+        TileWindow { origin_x: x * width, origin_y: y * height, width, height, tile_size }
+    }
+
+    /// The source-pixel block of `grid` (a `world`-sized layer) covered by
+    /// tile pixel `(tx, ty)`.
+    fn block(&self, world: &World, grid: &[u16], tx: u32, ty: u32) -> Vec<u16> {
+        let x0 = self.origin_x + tx * self.width / self.tile_size;
+        let x1 = self.origin_x + (tx + 1) * self.width / self.tile_size;
+        let y0 = self.origin_y + ty * self.height / self.tile_size;
+        let y1 = self.origin_y + (ty + 1) * self.height / self.tile_size;
+
+        let mut block = Vec::new();
+        for sy in y0..y1.max(y0 + 1).min(world.height) {
+            for sx in x0..x1.max(x0 + 1).min(world.width) {
+                block.push(grid[(sy * world.width + sx) as usize]);
+            }
+        }
+        block
+    }
+}
+
+/// Renders one 256x256 PNG tile for `layer` at slippy-map coordinate
+/// `(z, x, y)`, downsampling the source-pixel window that tile covers the
+/// same way `set_pixels` downsamples the whole image.
+fn render_tile(
+    world: &World,
+    layer: &str,
+    z: u32,
+    x: u32,
+    y: u32,
+    palettes: Option<&HashMap<String, Palette>>,
+) -> Option<Vec<u8>> {
+    const TILE_SIZE: u32 = 256;
+    let window = TileWindow::new(world, z, x, y, TILE_SIZE);
+
+    let mut tile = ImageBuffer::new(TILE_SIZE, TILE_SIZE);
+    if layer == "biome" {
+        for (tx, ty, pixel) in tile.enumerate_pixels_mut() {
+            let temperature = window.block(world, &world.temperature, tx, ty);
+            let moisture = window.block(world, &world.moisture, tx, ty);
+            *pixel = biome_color(&temperature, &moisture);
+        }
+    } else {
+        let classes = layer_classes(world, layer)?;
+        for (tx, ty, pixel) in tile.enumerate_pixels_mut() {
+            let block = window.block(world, classes, tx, ty);
+            let class = if block.is_empty() { 0 } else { most_common(&block) };
+            *pixel = layer_color(layer, class, palettes);
+        }
+    }
+
+    let mut png_bytes = Vec::new();
+    tile.write_to(&mut std::io::Cursor::new(&mut png_bytes), image::ImageFormat::Png)
+        .ok()?;
+    Some(png_bytes)
+}
+
+/// Parses a `/{layer}/{z}/{x}/{y}.png` slippy-map tile request path.
+fn parse_tile_path(path: &str) -> Option<(&str, u32, u32, u32)> {
+    let path = path.trim_start_matches('/').strip_suffix(".png")?;
+    let mut parts = path.split('/');
+    let layer = parts.next()?;
+    let z = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((layer, z, x, y))
 }
 
-fn map_pixel(pixel_value: u16, mappings: &[PixelMapping]) -> &PixelMapping {
-    for mapping in mappings {
-        if mapping.value == pixel_value {
-            return mapping;
+/// Serves `world`'s layers as on-demand XYZ/slippy PNG tiles over HTTP.
+///
+/// `GET /{layer}/{z}/{x}/{y}.png` renders just the source-pixel window that
+/// tile covers, so the full-resolution map never has to be exported to a
+/// giant PNG up front.
+fn serve_tiles(world: World, palettes: Option<HashMap<String, Palette>>, port: u16) -> Result<(), ConvertError> {
+    let server = tiny_http::Server::http(("0.0.0.0", port))
+        .map_err(|e| ConvertError::Cli(format!("failed to bind HTTP server on port {}: {}", port, e)))?;
+    println!("Serving tiles on http://0.0.0.0:{}/{{layer}}/{{z}}/{{x}}/{{y}}.png", port);
+
+    for request in server.incoming_requests() {
+        let url = request.url().to_string();
+        let response = match parse_tile_path(&url) {
+            Some((layer, z, x, y)) => match render_tile(&world, layer, z, x, y, palettes.as_ref()) {
+                Some(png) => {
+                    let content_type =
+                        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"image/png"[..]).unwrap();
+                    tiny_http::Response::from_data(png)
+                        .with_header(content_type)
+                        .boxed()
+                }
+                None => tiny_http::Response::from_string(format!("unknown layer: {}", layer))
+                    .with_status_code(404)
+                    .boxed(),
+            },
+            None => tiny_http::Response::from_string("expected /{layer}/{z}/{x}/{y}.png")
+                .with_status_code(400)
+                .boxed(),
+        };
+        if let Err(e) = request.respond(response) {
+            eprintln!("warning: failed to respond to tile request: {}", e);
         }
     }
-    panic!("No mapping found for pixel value {}", pixel_value);
+    Ok(())
 }
 
 fn most_common(terrain: &[u16]) -> u16 {
@@ -175,22 +757,104 @@ fn most_common(terrain: &[u16]) -> u16 {
     most_common
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        println!("Usage: {} <input_file> <output_folder>", args[0]);
-        return;
+/// Brightness delta `--hillshade` adds to a pixel higher than its northern
+/// neighbor (and subtracts from one lower), whatever the height difference,
+/// when no explicit `--hillshade=N` value is given.
+const DEFAULT_HILLSHADE_DELTA: i16 = 24;
+
+/// Pulls a `--hillshade` or `--hillshade=<delta>` flag out of `args`
+/// (removing it in place) and returns the delta to use, if present.
+fn take_hillshade_flag(args: &mut Vec<String>) -> Result<Option<i16>, ConvertError> {
+    let pos = match args.iter().position(|a| a == "--hillshade" || a.starts_with("--hillshade=")) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let flag = args.remove(pos);
+    let delta = match flag.strip_prefix("--hillshade=") {
+        Some(value) => value
+            .parse()
+            .map_err(|_| ConvertError::Cli(format!("--hillshade delta must be a number, got {:?}", value)))?,
+        None => DEFAULT_HILLSHADE_DELTA,
+    };
+    Ok(Some(delta))
+}
+
+fn run() -> Result<(), ConvertError> {
+    let mut args: Vec<String> = env::args().collect();
+    let hillshade_delta = take_hillshade_flag(&mut args)?;
+
+    if args.get(1).map(|s| s.as_str()) == Some("serve") {
+        if args.len() < 4 || args.len() > 5 {
+            println!("Usage: {} serve <input_file> <port> [palette_config]", args[0]);
+            return Ok(());
+        }
+        let input_file = &args[2];
+        let port: u16 = args[3]
+            .parse()
+            .map_err(|_| ConvertError::Cli(format!("port must be a number between 0 and 65535, got {:?}", args[3])))?;
+        let config_path = args.get(4).map(|s| s.as_str());
+
+        let palettes = load_palette_config(config_path)?;
+        let world = load_or_build_world(input_file, "world.dbf")?;
+        return serve_tiles(world, palettes, port);
+    }
+
+    if args.len() < 3 || args.len() > 4 {
+        println!("Usage: {} <input_file> <output_folder> [palette_config] [--hillshade[=delta]]", args[0]);
+        println!("       {} serve <input_file> <port> [palette_config]", args[0]);
+        return Ok(());
     }
     let input_file = &args[1];
     let output_folder = &args[2];
+    let config_path = args.get(3).map(|s| s.as_str());
     let scale = 4;
-    let imgs = decode_image(input_file, "world.dbf", scale);
-    
+
+    let palettes = load_palette_config(config_path)?;
+    let imgs = decode_image(input_file, "world.dbf", scale, palettes.as_ref(), hillshade_delta)?;
+
+    // Layers the pipeline actually computes, in the order `decode_image` returns
+    // them. A config can select a subset (and recolor/rename it) but can't
+    // introduce a brand new DBF-derived layer by itself — that still requires
+    // extending `PixelMapping`/`World` with the new field.
+    let known_layers = ["terrain", "vegetation", "temperature", "moisture", "biome"];
+    let images_by_layer: HashMap<&str, &ImageBuffer<Rgb<u8>, Vec<u8>>> =
+        known_layers.into_iter().zip(imgs.iter()).collect();
+
+    let layer_names: Vec<&str> = match palettes.as_ref() {
+        Some(palettes) => {
+            let mut names: Vec<&str> = Vec::new();
+            for key in palettes.keys() {
+                if images_by_layer.contains_key(key.as_str()) {
+                    names.push(key.as_str());
+                } else {
+                    eprintln!(
+                        "warning: palette config defines layer {:?}, but only {:?} are computed from the DBF; skipping",
+                        key, known_layers
+                    );
+                }
+            }
+            if names.is_empty() { known_layers.to_vec() } else { names }
+        }
+        None => known_layers.to_vec(),
+    };
+
     println!("Saving images...");
-    imgs[0].save(format!("{}/terrain.png", output_folder)).unwrap();
-    imgs[1].save(format!("{}/vegetation.png", output_folder)).unwrap();
-    imgs[2].save(format!("{}/temperature.png", output_folder)).unwrap();
-    imgs[3].save(format!("{}/moisture.png", output_folder)).unwrap();
+    for name in layer_names {
+        let output = palettes
+            .as_ref()
+            .and_then(|p| p.get(name))
+            .map(|p| p.output.clone())
+            .unwrap_or_else(|| format!("{}.png", name));
+        images_by_layer[name].save(format!("{}/{}", output_folder, output)).unwrap();
+    }
     println!("Done!");
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
 